@@ -6,7 +6,7 @@ use super::{
     varint::VarInt,
 };
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct StreamType(u64);
 
 macro_rules! stream_types {
@@ -22,6 +22,13 @@ stream_types! {
     PUSH = 0x01,
     ENCODER = 0x02,
     DECODER = 0x03,
+    // https://www.ietf.org/archive/id/draft-ietf-webtrans-http3-02.html
+    //
+    // The bidirectional WebTransport data-stream signal (0x41) is an HTTP/3
+    // frame type read on an already-open bidi stream, not a unidirectional
+    // `StreamType` prefix byte, so it has no constant here; nothing in this
+    // module recognizes it.
+    WEBTRANSPORT_UNI = 0x54,
 }
 
 impl StreamType {
@@ -48,6 +55,7 @@ impl fmt::Display for StreamType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             &StreamType::CONTROL => write!(f, "Control"),
+            &StreamType::PUSH => write!(f, "Push"),
             &StreamType::ENCODER => write!(f, "Encoder"),
             &StreamType::DECODER => write!(f, "Decoder"),
             x => write!(f, "StreamType({})", x.0),
@@ -55,6 +63,215 @@ impl fmt::Display for StreamType {
     }
 }
 
+/// The type of a freshly-accepted stream, resolved from the raw
+/// [`StreamType`] (and, for variants that carry one, the id that follows it)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NewStreamType {
+    Control,
+    Push(u64),
+    Encoder,
+    Decoder,
+    WebTransportStream(u64),
+    /// An ordinary bidirectional HTTP/3 request stream
+    Http,
+    /// A unidirectional stream type this implementation does not recognize
+    Unknown,
+}
+
+/// Error encountered while classifying a freshly-accepted stream
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NewStreamTypeError {
+    /// `role` is not allowed to receive a stream of type `ty`
+    InvalidRole { role: Side, ty: StreamType },
+    /// Ran out of bytes while decoding the stream type prefix
+    UnexpectedEnd,
+}
+
+impl fmt::Display for NewStreamTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRole { role, ty } => {
+                write!(
+                    f,
+                    "{:?} is not allowed to receive a stream of type {}",
+                    role, ty
+                )
+            }
+            Self::UnexpectedEnd => write!(f, "unexpected end of buffer"),
+        }
+    }
+}
+
+impl std::error::Error for NewStreamTypeError {}
+
+impl From<UnexpectedEnd> for NewStreamTypeError {
+    fn from(_: UnexpectedEnd) -> Self {
+        Self::UnexpectedEnd
+    }
+}
+
+impl NewStreamType {
+    /// Classify a freshly-accepted stream of direction `dir`, received while
+    /// acting as `role`.
+    ///
+    /// Every bidirectional stream is classified as [`NewStreamType::Http`]
+    /// without reading `buf`; the bidirectional WebTransport data-stream
+    /// signal (0x41) is not recognized. `buf` is only consulted for
+    /// unidirectional streams: the leading
+    /// [`StreamType`] is read first, and for [`StreamType::PUSH`] and
+    /// [`StreamType::WEBTRANSPORT_UNI`] a second varint (the push id / the
+    /// WebTransport session id) is read immediately after it.
+    pub fn classify<B: Buf>(dir: Dir, role: Side, buf: &mut B) -> Result<Self, NewStreamTypeError> {
+        if dir == Dir::Bi {
+            return Ok(Self::Http);
+        }
+
+        let ty = StreamType::decode(buf)?;
+        Ok(match ty {
+            StreamType::CONTROL => Self::Control,
+            StreamType::ENCODER => Self::Encoder,
+            StreamType::DECODER => Self::Decoder,
+            StreamType::PUSH => {
+                check_role(role, ty)?;
+                Self::Push(buf.get_var()?)
+            }
+            StreamType::WEBTRANSPORT_UNI => Self::WebTransportStream(buf.get_var()?),
+            _ => Self::Unknown,
+        })
+    }
+}
+
+/// Only the client, the side which initiates the connection's requests, may
+/// receive a push stream: a server never sees one from a well-behaved client
+fn check_role(role: Side, ty: StreamType) -> Result<(), NewStreamTypeError> {
+    if ty == StreamType::PUSH && role == Side::Server {
+        return Err(NewStreamTypeError::InvalidRole { role, ty });
+    }
+    Ok(())
+}
+
+/// Result of feeding a [`StreamTypeReader`] another chunk of bytes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StreamTypePoll {
+    /// Not enough bytes have arrived yet to finish decoding the prefix
+    Pending,
+    /// The prefix has been fully decoded
+    Done(NewStreamType),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReaderState {
+    /// Reading the leading [`StreamType`] varint
+    Type,
+    /// The type is known and carries a second (push id / session id) varint
+    Id(StreamType),
+}
+
+/// Incrementally decodes the unidirectional stream-type prefix described by
+/// [`NewStreamType::classify`], a byte or more at a time.
+///
+/// Unlike [`NewStreamType::classify`], which expects the whole prefix to
+/// already be buffered, `StreamTypeReader` is meant for a real QUIC
+/// unidirectional stream, where the one-to-eight-byte type code (and, for
+/// [`StreamType::PUSH`] / [`StreamType::WEBTRANSPORT_UNI`], the id varint
+/// that follows it) can arrive split across reads. Bytes are fed in with
+/// [`push`](StreamTypeReader::push), which only consumes what belongs to the
+/// prefix, leaving the rest of `buf` for the frame layer.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTypeReader {
+    role: Side,
+    state: ReaderState,
+    buf: [u8; VarInt::MAX_SIZE],
+    len: usize,
+}
+
+impl StreamTypeReader {
+    /// Create a reader for a unidirectional stream received while acting as `role`
+    pub fn new(role: Side) -> Self {
+        Self {
+            role,
+            state: ReaderState::Type,
+            buf: [0; VarInt::MAX_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Feed newly-available bytes to the reader
+    ///
+    /// Returns [`StreamTypePoll::Pending`] if `buf` ran out before the
+    /// prefix could be fully decoded; call `push` again once more bytes have
+    /// arrived. Only bytes belonging to the prefix are consumed from `buf`.
+    pub fn push<B: Buf>(&mut self, buf: &mut B) -> Result<StreamTypePoll, NewStreamTypeError> {
+        loop {
+            match self.state {
+                ReaderState::Type => {
+                    let ty = match self.poll_varint(buf)? {
+                        Some(v) => StreamType(v),
+                        None => return Ok(StreamTypePoll::Pending),
+                    };
+                    self.state = match ty {
+                        StreamType::CONTROL => {
+                            return Ok(StreamTypePoll::Done(NewStreamType::Control))
+                        }
+                        StreamType::ENCODER => {
+                            return Ok(StreamTypePoll::Done(NewStreamType::Encoder))
+                        }
+                        StreamType::DECODER => {
+                            return Ok(StreamTypePoll::Done(NewStreamType::Decoder))
+                        }
+                        StreamType::PUSH => {
+                            check_role(self.role, ty)?;
+                            ReaderState::Id(ty)
+                        }
+                        StreamType::WEBTRANSPORT_UNI => ReaderState::Id(ty),
+                        _ => return Ok(StreamTypePoll::Done(NewStreamType::Unknown)),
+                    };
+                }
+                ReaderState::Id(ty) => {
+                    let id = match self.poll_varint(buf)? {
+                        Some(v) => v,
+                        None => return Ok(StreamTypePoll::Pending),
+                    };
+                    return Ok(StreamTypePoll::Done(match ty {
+                        StreamType::PUSH => NewStreamType::Push(id),
+                        StreamType::WEBTRANSPORT_UNI => NewStreamType::WebTransportStream(id),
+                        _ => unreachable!("only PUSH and WEBTRANSPORT_UNI carry a second varint"),
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Accumulate bytes for a single varint across calls to `push`,
+    /// returning `Some` only once the full varint (whose length is
+    /// determined from its first byte's two high bits) has arrived
+    fn poll_varint<B: Buf>(&mut self, buf: &mut B) -> Result<Option<u64>, NewStreamTypeError> {
+        if self.len == 0 {
+            if !buf.has_remaining() {
+                return Ok(None);
+            }
+            self.buf[0] = buf.get_u8();
+            self.len = 1;
+        }
+
+        let needed = 1usize << (self.buf[0] >> 6);
+        while self.len < needed {
+            if !buf.has_remaining() {
+                return Ok(None);
+            }
+            self.buf[self.len] = buf.get_u8();
+            self.len += 1;
+        }
+
+        let mut consumed = &self.buf[..needed];
+        let value = VarInt::decode(&mut consumed)
+            .expect("length was derived from the varint's own prefix")
+            .into_inner();
+        self.len = 0;
+        Ok(Some(value))
+    }
+}
+
 /// Identifier for a stream
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct StreamId(
@@ -83,6 +300,30 @@ impl fmt::Display for StreamId {
 }
 
 impl StreamId {
+    /// The largest stream id representable in the 62-bit varint encoding
+    pub const MAX: StreamId = StreamId((1 << 62) - 1);
+
+    /// Create a new StreamId for the stream opened by `initiator`, in
+    /// direction `dir`, at `index`
+    ///
+    /// `index` must not exceed `StreamId::MAX.index()`; callers allocating
+    /// successive ids should go through [`try_next`](StreamId::try_next)
+    /// instead, which enforces this at runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics, in every build profile, if `index` exceeds
+    /// `StreamId::MAX.index()`, so that an out-of-range id can never reach
+    /// `Encode` and panic there instead.
+    pub fn new(initiator: Side, dir: Dir, index: u64) -> Self {
+        assert!(
+            index <= Self::MAX.index(),
+            "StreamId index {} exceeds StreamId::MAX.index()",
+            index
+        );
+        Self(index << 2 | (dir as u64) << 1 | initiator as u64)
+    }
+
     /// Distinguishes streams of the same initiator and directionality
     pub fn index(self) -> u64 {
         self.0 >> 2
@@ -96,7 +337,22 @@ impl StreamId {
         self.dir() == Dir::Uni && self.initiator() == Side::Server
     }
 
-    /// Create a new StreamId
+    /// The index of the next stream with the same initiator and direction as
+    /// this one, or [`StreamIdOverflow`] if that would exceed [`StreamId::MAX`]
+    pub fn next_index(self) -> Result<u64, StreamIdOverflow> {
+        let next = self.index().checked_add(1).ok_or(StreamIdOverflow(()))?;
+        if next > Self::MAX.index() {
+            return Err(StreamIdOverflow(()));
+        }
+        Ok(next)
+    }
+
+    /// The `StreamId` that follows this one, with the same initiator and
+    /// direction, or [`StreamIdOverflow`] if that would exceed [`StreamId::MAX`]
+    pub fn try_next(self) -> Result<Self, StreamIdOverflow> {
+        Ok(Self::new(self.initiator(), self.dir(), self.next_index()?))
+    }
+
     /// Which side of a connection initiated the stream
     fn initiator(self) -> Side {
         if self.0 & 0x1 == 0 {
@@ -116,19 +372,41 @@ impl StreamId {
 }
 
 impl From<u64> for StreamId {
+    /// Wrap a raw id already produced by the QUIC layer, which enforces the
+    /// same 62-bit varint limit as [`StreamId::MAX`]
     fn from(v: u64) -> Self {
+        debug_assert!(
+            v <= Self::MAX.0,
+            "StreamId {} exceeds StreamId::MAX; the QUIC layer should never hand out one that does",
+            v
+        );
         Self(v)
     }
 }
 
 impl Encode for StreamId {
     fn encode<B: bytes::BufMut>(&self, buf: &mut B) {
-        VarInt::from_u64(self.0).unwrap().encode(buf);
+        VarInt::from_u64(self.0)
+            .expect("StreamId exceeds StreamId::MAX; this is a bug in how it was constructed")
+            .encode(buf);
+    }
+}
+
+/// Error indicating that a [`StreamId`] computation would exceed [`StreamId::MAX`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StreamIdOverflow(());
+
+impl fmt::Display for StreamIdOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream ID exceeded the maximum value")
     }
 }
 
+impl std::error::Error for StreamIdOverflow {}
+
+/// Which side of a connection initiated the stream
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum Side {
+pub enum Side {
     /// The initiator of a connection
     Client = 0,
     /// The acceptor of a connection
@@ -137,9 +415,76 @@ enum Side {
 
 /// Whether a stream communicates data in both directions or only from the initiator
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum Dir {
+pub enum Dir {
     /// Data flows in both directions
     Bi = 0,
     /// Data flows only from the stream's initiator
     Uni = 1,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_id_try_next_stops_at_max() {
+        // StreamId::MAX is all-ones, so it's the (Server, Uni) id at MAX.index()
+        let last = StreamId::new(Side::Server, Dir::Uni, StreamId::MAX.index());
+        assert_eq!(last, StreamId::MAX);
+        assert!(last.try_next().is_err());
+    }
+
+    #[test]
+    fn stream_id_try_next_below_max_succeeds() {
+        let id = StreamId::new(Side::Server, Dir::Uni, StreamId::MAX.index() - 1);
+        let next = id.try_next().expect("index is still within range");
+        assert_eq!(next.index(), StreamId::MAX.index());
+        assert_eq!(next, StreamId::MAX);
+    }
+
+    #[test]
+    fn stream_type_reader_handles_bytes_split_across_pushes() {
+        let mut reader = StreamTypeReader::new(Side::Client);
+
+        // StreamType::WEBTRANSPORT_UNI (0x54) needs a 2-byte varint (0x40, 0x54);
+        // feed it one byte at a time, then the 1-byte session id varint.
+        let mut first = &[0x40u8][..];
+        assert_eq!(reader.push(&mut first).unwrap(), StreamTypePoll::Pending);
+
+        let mut second = &[0x54u8][..];
+        assert_eq!(reader.push(&mut second).unwrap(), StreamTypePoll::Pending);
+
+        let mut session_id = &[0x07u8][..];
+        assert_eq!(
+            reader.push(&mut session_id).unwrap(),
+            StreamTypePoll::Done(NewStreamType::WebTransportStream(7))
+        );
+    }
+
+    #[test]
+    fn stream_type_reader_does_not_over_read_past_the_prefix() {
+        let mut reader = StreamTypeReader::new(Side::Client);
+        // CONTROL (0x00) is a 1-byte varint; the following bytes belong to
+        // whatever comes after the prefix and must be left untouched.
+        let mut buf = &[0x00u8, 0xaa, 0xbb][..];
+        assert_eq!(
+            reader.push(&mut buf).unwrap(),
+            StreamTypePoll::Done(NewStreamType::Control)
+        );
+        assert_eq!(buf, &[0xaa, 0xbb][..]);
+    }
+
+    #[test]
+    fn stream_type_reader_rejects_push_stream_for_server() {
+        let mut reader = StreamTypeReader::new(Side::Server);
+        let mut buf = &[0x01u8][..]; // StreamType::PUSH
+        let err = reader.push(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            NewStreamTypeError::InvalidRole {
+                role: Side::Server,
+                ty
+            } if ty == StreamType::PUSH
+        ));
+    }
+}